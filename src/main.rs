@@ -1,13 +1,25 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::rc::Rc;
 use std::cell::RefCell;
 
-use rand::{thread_rng, Rng};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+// The weight a nice-0 (default priority) task runs with. vruntime accrues
+// slower for heavier (higher-weight) tasks, so they get picked more often.
+const NICE_0_WEIGHT: u64 = 1024;
 
 struct Cpu {
     id: usize,
     clock: u64,
-    runq: VecDeque<Rc<RefCell<Task>>>,
+    // Ordered by (vruntime, task id) so the task with the least accumulated
+    // virtual runtime is always first; the task id is just a tiebreak for
+    // tasks that land on the same vruntime.
+    runq: BTreeMap<(u64, u64), Rc<RefCell<Task>>>,
+    min_vruntime: u64,
+    // Tasks blocked on simulated I/O, keyed by the clock tick they should
+    // wake up at. A CPU's own clock is what drives this timer wheel.
+    wait_queue: BTreeMap<u64, Vec<Rc<RefCell<Task>>>>,
     running_task: Rc<RefCell<Task>>,
     idle_task: Rc<RefCell<Task>>
 }
@@ -21,33 +33,28 @@ impl Cpu {
         Cpu {
             id: id,
             clock: 0,
-            runq: VecDeque::new(),
+            runq: BTreeMap::new(),
+            min_vruntime: 0,
+            wait_queue: BTreeMap::new(),
             running_task: idle_task_ref.clone(),
             idle_task: idle_task_ref.clone(),
         }
     }
 
+    // Enqueue a brand-new task. Its vruntime is bumped up to
+    // the CPU's current watermark so it can't monopolize the CPU on the back
+    // of having sat at vruntime 0 while it was off the runq.
     fn add_task(&mut self, task: Rc<RefCell<Task>>) {
-        self.runq.push_back(task.clone());
+        task.borrow_mut().vruntime = self.min_vruntime;
+        let key = (task.borrow().vruntime, task.borrow().id);
+        self.runq.insert(key, task);
     }
 
-    fn next_task(&mut self) {
-        let old_task = self.running_task.clone();
-        if old_task.borrow().id != self.idle_task.borrow().id {
-            self.runq.push_back(old_task.clone());
-        }
-
-        let new_task = self.runq.pop_front();
-        let new_task_or_idle = new_task.unwrap_or(self.idle_task.clone());
-        self.running_task = new_task_or_idle;
-        self.running_task.borrow_mut().state = TaskState::RUNNING;
-
-        let task_slice_output = self.running_task.borrow_mut().run(self);
-        self.clock += task_slice_output.clock_consumed;
-        match task_slice_output.next_state {
-            TaskState::RUNNING => panic!("next state should be RUNNABLE or WAIT"),
-            _ => self.running_task.borrow_mut().state = task_slice_output.next_state
-        }
+    // Re-enqueue a task that already has earned vruntime (e.g. it was just
+    // preempted, or stolen in from another CPU) without resetting it.
+    fn requeue(&mut self, task: Rc<RefCell<Task>>) {
+        let key = (task.borrow().vruntime, task.borrow().id);
+        self.runq.insert(key, task);
     }
 }
 
@@ -61,13 +68,18 @@ enum TaskState {
 trait TaskImpl {
     fn name(&self) -> &str;
 
-    // returns cpu time consumed
-    fn do_work(&mut self) -> u64;
+    // Returns (cpu time consumed, ticks to stay blocked for). The second
+    // element is `Some(sleep_ticks)` when the task wants to block on
+    // simulated I/O after this slice, and `None` if it's staying runnable.
+    // Takes the scheduler's rng so that, given a seed, a run is reproducible.
+    fn do_work(&mut self, rng: &mut StdRng) -> (u64, Option<u64>);
 }
 
 struct TaskSliceOutput {
     next_state: TaskState,
-    clock_consumed: u64
+    clock_consumed: u64,
+    // Absolute clock tick to wake up at. Only set when next_state is WAIT.
+    wake_at: Option<u64>,
 }
 
 struct Task {
@@ -75,37 +87,75 @@ struct Task {
     state: TaskState,
     total_runtime: u64,
     task_impl: Box<dyn TaskImpl>,
+    priority: i32,
+    weight: u64,
+    vruntime: u64,
+    // Home CPU id, if this task is homed anywhere at all.
+    affinity: Option<usize>,
+    // When true, the task must never be stolen off its home CPU; work
+    // stealing skips it and it's migrated back home when possible.
+    pinned: bool,
 }
 
 impl Task {
     fn new(id: u64, task_impl: Box<dyn TaskImpl>) -> Self {
+        Task::with_priority(id, task_impl, 0)
+    }
+
+    fn with_priority(id: u64, task_impl: Box<dyn TaskImpl>, priority: i32) -> Self {
         Task {
             id: id,
             state: TaskState::RUNNABLE,
             total_runtime: 0,
-            task_impl: task_impl
+            task_impl: task_impl,
+            priority: priority,
+            weight: weight_for_priority(priority),
+            vruntime: 0,
+            affinity: None,
+            pinned: false,
         }
     }
 
-    fn run(&mut self, cpu: &Cpu) -> TaskSliceOutput {
-        println!("task {} ({}) running on cpu {}, total runtime {}", self.id, self.task_impl.name(), cpu.id, self.total_runtime);
-        let work_quantity = self.task_impl.do_work();
+    fn run(&mut self, cpu: &Cpu, rng: &mut StdRng) -> TaskSliceOutput {
+        println!("task {} ({}) running on cpu {}, total runtime {}, vruntime {}", self.id, self.task_impl.name(), cpu.id, self.total_runtime, self.vruntime);
+        let (work_quantity, sleep_ticks) = self.task_impl.do_work(rng);
         self.total_runtime += work_quantity;
-        TaskSliceOutput {
-            next_state: TaskState::RUNNABLE,
-            clock_consumed: work_quantity
+        self.vruntime += work_quantity * NICE_0_WEIGHT / self.weight;
+
+        match sleep_ticks {
+            Some(sleep) => TaskSliceOutput {
+                next_state: TaskState::WAIT,
+                clock_consumed: work_quantity,
+                wake_at: Some(cpu.clock + work_quantity + sleep),
+            },
+            None => TaskSliceOutput {
+                next_state: TaskState::RUNNABLE,
+                clock_consumed: work_quantity,
+                wake_at: None,
+            }
         }
     }
 }
 
+// Mirrors the idea behind Linux's nice-to-weight table: each priority step
+// away from 0 scales a task's effective CPU share by a fixed factor, so
+// vruntime (which is weighted by this) accrues faster for low-priority
+// tasks and slower for high-priority ones.
+fn weight_for_priority(priority: i32) -> u64 {
+    let scale = 1.25f64.powi(-priority);
+    // Clamp to 1: weight is later used as a vruntime divisor, and an
+    // extreme (if valid) priority would otherwise round this to 0.
+    (((NICE_0_WEIGHT as f64) * scale).round() as u64).max(1)
+}
+
 struct IdleTask;
 impl TaskImpl for IdleTask {
     fn name(&self) -> &str {
         return "idle";
     }
 
-    fn do_work(&mut self) -> u64 {
-        return 1;
+    fn do_work(&mut self, _rng: &mut StdRng) -> (u64, Option<u64>) {
+        return (1, None);
     }
 }
 
@@ -115,25 +165,75 @@ impl TaskImpl for RandomUserTask {
         return "randomuser";
     }
 
-    fn do_work(&mut self) -> u64 {
-        let mut rng = thread_rng();
+    fn do_work(&mut self, rng: &mut StdRng) -> (u64, Option<u64>) {
         let quantity: u64 = rng.gen_range(1..1000) as u64;
-        return quantity;
+        return (quantity, None);
+    }
+}
+
+// Alternates bursts of CPU work with stretches of blocking on simulated
+// I/O, to exercise the WAIT / wakeup path the way a task doing real
+// network or disk I/O would.
+struct BlockingUserTask {
+    work_remaining: u64,
+}
+
+impl BlockingUserTask {
+    fn new(rng: &mut StdRng) -> Self {
+        BlockingUserTask {
+            work_remaining: rng.gen_range(100..400),
+        }
+    }
+}
+
+impl TaskImpl for BlockingUserTask {
+    fn name(&self) -> &str {
+        return "blockinguser";
+    }
+
+    fn do_work(&mut self, rng: &mut StdRng) -> (u64, Option<u64>) {
+        let quantity = rng.gen_range(1..200).min(self.work_remaining);
+        self.work_remaining -= quantity;
+
+        if self.work_remaining == 0 {
+            let sleep_for = rng.gen_range(50..500);
+            self.work_remaining = rng.gen_range(100..400);
+            return (quantity, Some(sleep_for));
+        }
+
+        (quantity, None)
     }
 }
 
 
 struct Scheduler {
-    cpus: Vec<Cpu>
+    cpus: Vec<Cpu>,
+    rng: StdRng,
+    // Which task each CPU dispatched at each step, in the order next_task
+    // was called. A seed plus this trace fully determines a run.
+    trace: Vec<(usize, u64)>,
+    // When set, next_task follows this recorded trace instead of consulting
+    // the scheduling policy, to deterministically reproduce a prior run.
+    replay_trace: Option<VecDeque<(usize, u64)>>,
 }
 
 impl Scheduler {
-    fn new() -> Self {
+    fn new(seed: u64) -> Self {
         Scheduler {
             cpus: Vec::new(),
+            rng: StdRng::seed_from_u64(seed),
+            trace: Vec::new(),
+            replay_trace: None,
         }
     }
 
+    // Force next_task to follow a previously recorded trace (see `trace`)
+    // instead of the scheduling policy, so a run that produced interesting
+    // idle/runtime numbers can be reproduced exactly for debugging.
+    fn replay(&mut self, trace: Vec<(usize, u64)>) {
+        self.replay_trace = Some(VecDeque::from(trace));
+    }
+
     fn add_cpus(&mut self, cpu_count: usize) {
         for cpu_id in 0..cpu_count {
             let cpu = Cpu::new(cpu_id);
@@ -147,14 +247,56 @@ impl Scheduler {
         let cpu_count = self.cpus.len();
 
         for task_id in first_task_id..first_task_id+task_count {
-            let task_impl = RandomUserTask {};
-            let task = Rc::new(RefCell::new(Task::new(task_id, Box::new(task_impl))));
+            // Spread tasks across a handful of nice levels so the
+            // weight/vruntime machinery in `weight_for_priority` actually
+            // sees non-zero priorities instead of every task defaulting to
+            // nice 0.
+            let priority = self.rng.gen_range(-3..=3);
+
+            // Roll for task type off the scheduler's rng rather than
+            // `task_id % 4`, which lines up with `task_id % cpu_count`
+            // below (cpu_count is 8) and would otherwise hand entire CPUs
+            // either all-blocking or all-CPU-bound tasks instead of a mix.
+            let task = if self.rng.gen_ratio(1, 4) {
+                Rc::new(RefCell::new(Task::with_priority(task_id, Box::new(BlockingUserTask::new(&mut self.rng)), priority)))
+            } else {
+                Rc::new(RefCell::new(Task::with_priority(task_id, Box::new(RandomUserTask {}), priority)))
+            };
             let cpu_id = (task_id % (cpu_count as u64)) as usize;
+            task.borrow_mut().affinity = Some(cpu_id);
 
             self.cpus[cpu_id].add_task(task);
         }
     }
 
+    // Pin `task_id` to `cpu_id`: it becomes its home, work stealing will
+    // never carry the task away from it, and it's migrated back home
+    // whenever that CPU has room. The task may currently be running,
+    // runnable on some CPU, or blocked in a wait queue.
+    fn pin_task(&mut self, task_id: u64, cpu_id: usize) {
+        let task = self.find_task_by_id(task_id).expect("pin_task: no such task");
+        let mut task = task.borrow_mut();
+        task.affinity = Some(cpu_id);
+        task.pinned = true;
+    }
+
+    // Find the task with the given id wherever it currently lives (running,
+    // on a runq, or blocked in a wait queue) without removing it.
+    fn find_task_by_id(&self, task_id: u64) -> Option<Rc<RefCell<Task>>> {
+        for cpu in self.cpus.iter() {
+            if cpu.running_task.borrow().id == task_id {
+                return Some(cpu.running_task.clone());
+            }
+            if let Some((_, task)) = cpu.runq.iter().find(|(_, task)| task.borrow().id == task_id) {
+                return Some(task.clone());
+            }
+            if let Some(task) = cpu.wait_queue.values().flatten().find(|task| task.borrow().id == task_id) {
+                return Some(task.clone());
+            }
+        }
+        None
+    }
+
     fn run_forever(&mut self) {
         let mut i = 0;
         loop {
@@ -162,13 +304,246 @@ impl Scheduler {
                 break;
             }
 
-            for cpu in self.cpus.iter_mut() {
-                cpu.next_task();
+            for cpu_id in 0..self.cpus.len() {
+                self.next_task(cpu_id);
                 i += 1;
             }
         }
     }
 
+    fn next_task(&mut self, cpu_id: usize) {
+        let old_task = self.cpus[cpu_id].running_task.clone();
+        // A task that just blocked (WAIT) was already moved into the wait
+        // queue when its slice finished; it must not go back on the runq.
+        if old_task.borrow().id != self.cpus[cpu_id].idle_task.borrow().id
+            && old_task.borrow().state == TaskState::RUNNABLE {
+            let destination = self.requeue_destination(&old_task.borrow(), cpu_id);
+            self.cpus[destination].requeue(old_task.clone());
+        }
+
+        let dispatched = if self.replay_trace.is_some() {
+            // The recorded trace already names the task to dispatch, but the
+            // steal still has to happen: `do_work` draws from the same `rng`
+            // that `steal_work` does, and the tasks a steal moves decide
+            // whether later runq-empty checks (and so later draws) happen.
+            // Skipping it would desync the rng stream from the recorded run
+            // the moment a steal happened.
+            if self.cpus[cpu_id].runq.is_empty() {
+                self.steal_work(cpu_id);
+            }
+            self.next_task_from_replay(cpu_id)
+        } else {
+            if self.cpus[cpu_id].runq.is_empty() {
+                self.steal_work(cpu_id);
+            }
+            // pop_first always yields the runnable task with the smallest
+            // vruntime, i.e. the one owed the most CPU time.
+            self.cpus[cpu_id].runq.pop_first().map(|(_, task)| task)
+        };
+
+        let dispatched_or_idle = dispatched.unwrap_or(self.cpus[cpu_id].idle_task.clone());
+        self.cpus[cpu_id].running_task = dispatched_or_idle;
+        self.cpus[cpu_id].running_task.borrow_mut().state = TaskState::RUNNING;
+        let dispatched_vruntime = self.cpus[cpu_id].running_task.borrow().vruntime;
+        self.cpus[cpu_id].min_vruntime = self.cpus[cpu_id].min_vruntime.max(dispatched_vruntime);
+
+        self.trace.push((cpu_id, self.cpus[cpu_id].running_task.borrow().id));
+
+        let task = self.cpus[cpu_id].running_task.clone();
+        let task_slice_output = task.borrow_mut().run(&self.cpus[cpu_id], &mut self.rng);
+        self.cpus[cpu_id].clock += task_slice_output.clock_consumed;
+        self.wake_timers(cpu_id);
+
+        match task_slice_output.next_state {
+            TaskState::RUNNING => panic!("next state should be RUNNABLE or WAIT"),
+            TaskState::RUNNABLE => {
+                self.cpus[cpu_id].running_task.borrow_mut().state = TaskState::RUNNABLE;
+            }
+            TaskState::WAIT => {
+                let wake_at = task_slice_output.wake_at.expect("WAIT state requires a wake_at clock");
+                self.cpus[cpu_id].running_task.borrow_mut().state = TaskState::WAIT;
+                let task = self.cpus[cpu_id].running_task.clone();
+                // A pinned task going to sleep while off its home CPU
+                // should wait (and later be woken) on its home, the same
+                // as the RUNNABLE-preemption path above.
+                let destination = self.requeue_destination(&task.borrow(), cpu_id);
+                self.cpus[destination].wait_queue.entry(wake_at).or_default().push(task);
+            }
+        }
+    }
+
+    // Pull the next dispatch decision out of the replay trace instead of
+    // consulting the scheduling policy. The task it names may currently be
+    // sitting in any CPU's runq or wait queue (a recorded run may have
+    // stolen or woken it from anywhere), so we search for it.
+    fn next_task_from_replay(&mut self, cpu_id: usize) -> Option<Rc<RefCell<Task>>> {
+        let (expected_cpu_id, expected_task_id) = self.replay_trace.as_mut()
+            .and_then(|trace| trace.pop_front())
+            .expect("replay trace exhausted before the run finished");
+        assert_eq!(expected_cpu_id, cpu_id, "replay trace is out of sync with the scheduling order");
+
+        if expected_task_id == self.cpus[cpu_id].idle_task.borrow().id {
+            None
+        } else {
+            self.take_task_by_id(expected_task_id)
+        }
+    }
+
+    // Remove and return the task with the given id from wherever it
+    // currently lives (any CPU's runq or wait queue).
+    fn take_task_by_id(&mut self, task_id: u64) -> Option<Rc<RefCell<Task>>> {
+        for cpu in self.cpus.iter_mut() {
+            let key = cpu.runq.iter().find(|(_, task)| task.borrow().id == task_id).map(|(key, _)| *key);
+            if let Some(key) = key {
+                return cpu.runq.remove(&key);
+            }
+        }
+
+        for cpu in self.cpus.iter_mut() {
+            let found = cpu.wait_queue.iter().find_map(|(wake_at, tasks)| {
+                tasks.iter().position(|task| task.borrow().id == task_id).map(|pos| (*wake_at, pos))
+            });
+
+            if let Some((wake_at, pos)) = found {
+                let task = cpu.wait_queue.get_mut(&wake_at).unwrap().remove(pos);
+                if cpu.wait_queue[&wake_at].is_empty() {
+                    cpu.wait_queue.remove(&wake_at);
+                }
+                task.borrow_mut().state = TaskState::RUNNABLE;
+                return Some(task);
+            }
+        }
+
+        None
+    }
+
+    // Each time a CPU's clock advances, move any tasks whose wakeup time
+    // has passed out of the wait queue and back onto the runq as RUNNABLE.
+    fn wake_timers(&mut self, cpu_id: usize) {
+        let clock = self.cpus[cpu_id].clock;
+        let due: Vec<u64> = self.cpus[cpu_id].wait_queue.range(..=clock).map(|(wake_at, _)| *wake_at).collect();
+
+        for wake_at in due {
+            if let Some(tasks) = self.cpus[cpu_id].wait_queue.remove(&wake_at) {
+                for task in tasks {
+                    // A pinned task may have gone to sleep while off its
+                    // home CPU; send it home on wake-up the same as every
+                    // other requeue path does.
+                    let destination = self.requeue_destination(&task.borrow(), cpu_id);
+                    let min_vruntime = self.cpus[destination].min_vruntime;
+                    {
+                        let mut task = task.borrow_mut();
+                        task.state = TaskState::RUNNABLE;
+                        // Same watermark bump add_task gives a new task:
+                        // without it a task that slept a while comes back
+                        // with stale, far-behind vruntime and would
+                        // monopolize the CPU until it caught back up.
+                        task.vruntime = task.vruntime.max(min_vruntime);
+                    }
+                    self.cpus[destination].requeue(task);
+                }
+            }
+        }
+    }
+
+    // When `thief_id`'s runq runs dry, grab roughly half of another CPU's
+    // backlog instead of letting it go idle. We take the tasks with the
+    // largest vruntime (the coldest ones, furthest from running next) and
+    // leave the smaller-vruntime ("hotter") tasks with the victim; this is
+    // the same rough split classic work-stealing runqueues use.
+    fn steal_work(&mut self, thief_id: usize) {
+        let cpu_count = self.cpus.len();
+        let start = match self.pick_steal_start() {
+            Some(start) => start,
+            None => return,
+        };
+
+        for offset in 0..cpu_count {
+            let victim_id = (start + offset) % cpu_count;
+            if victim_id == thief_id {
+                continue;
+            }
+
+            let steal_count = self.cpus[victim_id].runq.len() / 2;
+            if steal_count == 0 {
+                continue;
+            }
+
+            // Walk the back of the victim's runq (coldest, largest-vruntime
+            // tasks first), skipping any task pinned to a home other than
+            // the thief so it can't be carried away from where it belongs.
+            let victim_keys: Vec<(u64, u64)> = self.cpus[victim_id].runq.keys().rev().copied().collect();
+            let mut keys_to_steal = Vec::with_capacity(steal_count);
+            for key in victim_keys {
+                if keys_to_steal.len() == steal_count {
+                    break;
+                }
+                let task = &self.cpus[victim_id].runq[&key];
+                let task_ref = task.borrow();
+                if task_ref.pinned && task_ref.affinity != Some(thief_id) {
+                    continue;
+                }
+                drop(task_ref);
+                keys_to_steal.push(key);
+            }
+
+            if keys_to_steal.is_empty() {
+                continue;
+            }
+
+            for key in keys_to_steal {
+                if let Some(task) = self.cpus[victim_id].runq.remove(&key) {
+                    self.cpus[thief_id].requeue(task);
+                }
+            }
+            return;
+        }
+    }
+
+    // Draw the rng value that picks where work-stealing starts its victim
+    // search, or None if there's nothing to steal from.
+    fn pick_steal_start(&mut self) -> Option<usize> {
+        let cpu_count = self.cpus.len();
+        if cpu_count <= 1 {
+            return None;
+        }
+        Some(self.rng.gen_range(0..cpu_count))
+    }
+
+    // Whether `home_id`'s runq is at least as free as `current_id`'s, i.e.
+    // sending a pinned task home wouldn't make it more backlogged than
+    // where it's currently sitting.
+    fn has_capacity(&self, home_id: usize, current_id: usize) -> bool {
+        self.cpus[home_id].runq.len() <= self.cpus[current_id].runq.len()
+    }
+
+    // Where a task that's about to land back on a runq or wait queue
+    // should actually go: its pinned home, if it isn't already there and
+    // that CPU has capacity, otherwise `current_cpu_id` (wherever it's
+    // sitting now). Shared by every path that puts a task back into
+    // circulation (preempted-RUNNABLE, just-blocked-WAIT, woken-from-sleep)
+    // so a pinned task is migrated home consistently no matter which state
+    // transition triggered it.
+    fn requeue_destination(&self, task: &Task, current_cpu_id: usize) -> usize {
+        match task.affinity {
+            Some(home_id) if task.pinned && home_id != current_cpu_id && self.has_capacity(home_id, current_cpu_id) => home_id,
+            _ => current_cpu_id,
+        }
+    }
+
+    // Which CPU currently holds the task with the given id (running,
+    // runnable, or blocked). For demonstrating/inspecting pinning.
+    fn cpu_of(&self, task_id: u64) -> Option<usize> {
+        for cpu in self.cpus.iter() {
+            if cpu.running_task.borrow().id == task_id
+                || cpu.runq.values().any(|task| task.borrow().id == task_id)
+                || cpu.wait_queue.values().flatten().any(|task| task.borrow().id == task_id) {
+                return Some(cpu.id);
+            }
+        }
+        None
+    }
+
     fn print_cpu_clocks(&self) {
         for cpu in self.cpus.iter() {
             println!("cpu {} has clock {}", cpu.id, cpu.clock);
@@ -178,27 +553,56 @@ impl Scheduler {
 
     fn print_task_runtime(&self) {
         for cpu in self.cpus.iter() {
-            for task in cpu.runq.iter() {
-                println!("task {} has total runtime {}", task.borrow().id, task.borrow().total_runtime);
+            for task in cpu.runq.values() {
+                let task = task.borrow();
+                println!("task {} has total runtime {}, priority {}, weight {}", task.id, task.total_runtime, task.priority, task.weight);
             }
         }
     }
 }
 
 fn main() {
-    let mut scheduler = Scheduler::new();
+    let seed = 42;
+    let mut scheduler = Scheduler::new(seed);
     scheduler.add_cpus(8);
     scheduler.add_tasks(64);
 
     println!("cpu0 tasks:");
-    for task in scheduler.cpus[0].runq.iter() {
+    for task in scheduler.cpus[0].runq.values() {
         println!("task id {}", task.borrow().id);
     }
 
+    // Pin a task to a CPU other than the one it landed on, to show both
+    // halves of pinning: it resists being stolen off its new home, and it
+    // migrates there from wherever it started.
+    let pinned_task_id = 1007;
+    let pinned_home = 2;
+    println!("pinning task {} (currently on cpu {:?}) to cpu {}", pinned_task_id, scheduler.cpu_of(pinned_task_id), pinned_home);
+    scheduler.pin_task(pinned_task_id, pinned_home);
+
     scheduler.run_forever();
 
     println!("###");
 
     scheduler.print_cpu_clocks();
     scheduler.print_task_runtime();
+    println!("pinned task {} ended up on cpu {:?}", pinned_task_id, scheduler.cpu_of(pinned_task_id));
+
+    // Replay: feed the trace this run just recorded into a fresh,
+    // identically-seeded scheduler and confirm it reproduces the same
+    // per-cpu clocks, the way a user would to debug a surprising run.
+    let trace = scheduler.trace.clone();
+    let mut replayed = Scheduler::new(seed);
+    replayed.add_cpus(8);
+    replayed.add_tasks(64);
+    replayed.pin_task(pinned_task_id, pinned_home);
+    replayed.replay(trace);
+    replayed.run_forever();
+
+    let original_clocks: Vec<u64> = scheduler.cpus.iter().map(|cpu| cpu.clock).collect();
+    let replayed_clocks: Vec<u64> = replayed.cpus.iter().map(|cpu| cpu.clock).collect();
+    assert_eq!(original_clocks, replayed_clocks, "replay did not reproduce the original run's per-cpu clocks");
+
+    println!("###");
+    println!("replay reproduced the original run's per-cpu clocks exactly");
 }